@@ -1,23 +1,174 @@
 use anyhow::Result;
+use futures_util::StreamExt;
+use regex::Regex;
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
+use std::collections::BTreeMap;
 use std::fs;
+use std::io::Write;
 use std::path::{Component, Path, PathBuf};
+use std::process::Command;
+use std::sync::mpsc;
+use std::time::UNIX_EPOCH;
+use threadpool::ThreadPool;
 
-/// The root directory the agent is allowed to operate in.
+/// Default root directory the agent is allowed to operate in, used when
+/// neither `--workspace` nor `ILLAMA_WORKSPACE` is set.
 ///
 /// IMPORTANT: the agent is SANDBOXED to this path; it cannot escape it.
-const WORKSPACE_ROOT: &str = "/home/pc2dev/ai_workspace";
+const DEFAULT_WORKSPACE_ROOT: &str = "/home/pc2dev/ai_workspace";
+
+/// Default `--alias` the agent asks llama-server for.
+const DEFAULT_MODEL: &str = "qwen2.5-coder-7b";
+
+/// Default chat-completions endpoint.
+const DEFAULT_BASE_URL: &str = "http://127.0.0.1:8080";
+
+/// Maximum number of LLM round-trips in a single REPL turn before `run`
+/// aborts that turn, so a model that keeps emitting tool calls can't loop forever.
+const DEFAULT_MAX_STEPS: usize = 25;
+
+/// Runtime configuration, overridable via CLI flags or env vars so the agent
+/// can target different workspaces/models/endpoints without recompiling.
+struct Config {
+    workspace_root: PathBuf,
+    model: String,
+    base_url: String,
+}
+
+impl Config {
+    /// Resolve config from CLI flags (`--workspace`, `--model`, `--base-url`),
+    /// falling back to `ILLAMA_WORKSPACE` / `ILLAMA_MODEL` / `ILLAMA_BASE_URL`
+    /// env vars, then to the built-in defaults. Any remaining, non-flag
+    /// arguments are returned separately to be joined into the initial task.
+    fn from_args(args: &[String]) -> (Config, Vec<String>) {
+        let mut workspace_root = std::env::var("ILLAMA_WORKSPACE").ok();
+        let mut model = std::env::var("ILLAMA_MODEL").ok();
+        let mut base_url = std::env::var("ILLAMA_BASE_URL").ok();
+        let mut rest = Vec::new();
+
+        let mut i = 0;
+        while i < args.len() {
+            match args[i].as_str() {
+                "--workspace" => {
+                    i += 1;
+                    if let Some(v) = args.get(i) {
+                        workspace_root = Some(v.clone());
+                    }
+                }
+                "--model" => {
+                    i += 1;
+                    if let Some(v) = args.get(i) {
+                        model = Some(v.clone());
+                    }
+                }
+                "--base-url" => {
+                    i += 1;
+                    if let Some(v) = args.get(i) {
+                        base_url = Some(v.clone());
+                    }
+                }
+                other => rest.push(other.to_string()),
+            }
+            i += 1;
+        }
+
+        let config = Config {
+            workspace_root: PathBuf::from(
+                workspace_root.unwrap_or_else(|| DEFAULT_WORKSPACE_ROOT.to_string()),
+            ),
+            model: model.unwrap_or_else(|| DEFAULT_MODEL.to_string()),
+            base_url: base_url.unwrap_or_else(|| DEFAULT_BASE_URL.to_string()),
+        };
+
+        (config, rest)
+    }
+}
 
 /// OpenAI-style chat message.
-#[derive(Debug, Serialize)]
+///
+/// `tool_calls` is set on assistant messages that invoked tools; `tool_call_id`
+/// is set on the `tool` role messages that answer them, per the OpenAI protocol.
+#[derive(Debug, Clone, Serialize)]
 struct ChatMessage {
     role: String,
-    content: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_calls: Option<Vec<ToolCallResponse>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_call_id: Option<String>,
 }
 
-/// Tool calls emitted by the model as pure JSON.
+impl ChatMessage {
+    fn system(content: impl Into<String>) -> Self {
+        ChatMessage {
+            role: "system".into(),
+            content: Some(content.into()),
+            tool_calls: None,
+            tool_call_id: None,
+        }
+    }
+
+    fn user(content: impl Into<String>) -> Self {
+        ChatMessage {
+            role: "user".into(),
+            content: Some(content.into()),
+            tool_calls: None,
+            tool_call_id: None,
+        }
+    }
+
+    fn assistant(content: impl Into<String>) -> Self {
+        ChatMessage {
+            role: "assistant".into(),
+            content: Some(content.into()),
+            tool_calls: None,
+            tool_call_id: None,
+        }
+    }
+
+    /// An assistant message that requested one or more native tool calls.
+    fn assistant_tool_calls(tool_calls: Vec<ToolCallResponse>) -> Self {
+        ChatMessage {
+            role: "assistant".into(),
+            content: None,
+            tool_calls: Some(tool_calls),
+            tool_call_id: None,
+        }
+    }
+
+    /// The `tool` role message that answers a single `tool_call_id`.
+    fn tool(tool_call_id: impl Into<String>, content: impl Into<String>) -> Self {
+        ChatMessage {
+            role: "tool".into(),
+            content: Some(content.into()),
+            tool_calls: None,
+            tool_call_id: Some(tool_call_id.into()),
+        }
+    }
+}
+
+/// A tool call as returned by the OpenAI-style `tool_calls` API.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct ToolCallResponse {
+    id: String,
+    #[serde(rename = "type")]
+    kind: String,
+    function: FunctionCall,
+}
+
+/// The `function` part of a [`ToolCallResponse`]; `arguments` is a JSON object
+/// encoded as a string, per the OpenAI/llama-server convention.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct FunctionCall {
+    name: String,
+    arguments: String,
+}
+
+/// Tool calls emitted by the model, either natively via `tool_calls` or as
+/// pure JSON in message content (see [`tool_call_from_function`]).
 #[derive(Debug, Deserialize)]
 #[serde(tag = "tool")]
 enum ToolCall {
@@ -29,6 +180,181 @@ enum ToolCall {
 
     #[serde(rename = "write_file")]
     WriteFile { path: String, content: String },
+
+    #[serde(rename = "search")]
+    Search {
+        path: String,
+        query: String,
+        regex: bool,
+    },
+
+    #[serde(rename = "metadata")]
+    Metadata { path: String },
+
+    #[serde(rename = "remove")]
+    Remove { path: String, recursive: bool },
+
+    #[serde(rename = "rename")]
+    Rename { src: String, dst: String },
+
+    #[serde(rename = "make_dir")]
+    MakeDir { path: String },
+
+    #[serde(rename = "cargo_check")]
+    CargoCheck { manifest_dir: String, clippy: bool },
+}
+
+/// Convert a native `FunctionCall` (name + JSON-string arguments) into a
+/// [`ToolCall`] by re-tagging the arguments with a `"tool"` field and
+/// deserializing through the same enum used for the content-sniffing path.
+fn tool_call_from_function(function: &FunctionCall) -> Result<ToolCall, String> {
+    let mut args: serde_json::Value = serde_json::from_str(&function.arguments)
+        .map_err(|e| format!("invalid arguments JSON for tool '{}': {e}", function.name))?;
+
+    if let serde_json::Value::Object(ref mut map) = args {
+        map.insert("tool".to_string(), json!(function.name));
+    }
+
+    serde_json::from_value(args)
+        .map_err(|e| format!("unknown tool or bad arguments for '{}': {e}", function.name))
+}
+
+/// JSON-schema function definitions for the `tools` array sent to the LLM.
+fn tool_schemas() -> serde_json::Value {
+    json!([
+        {
+            "type": "function",
+            "function": {
+                "name": "list_dir",
+                "description": "List the entries of a directory inside the workspace.",
+                "parameters": {
+                    "type": "object",
+                    "properties": {
+                        "path": { "type": "string", "description": "Path relative to the workspace root." }
+                    },
+                    "required": ["path"]
+                }
+            }
+        },
+        {
+            "type": "function",
+            "function": {
+                "name": "read_file",
+                "description": "Read a UTF-8 text file inside the workspace.",
+                "parameters": {
+                    "type": "object",
+                    "properties": {
+                        "path": { "type": "string", "description": "Path relative to the workspace root." }
+                    },
+                    "required": ["path"]
+                }
+            }
+        },
+        {
+            "type": "function",
+            "function": {
+                "name": "write_file",
+                "description": "Create or overwrite a UTF-8 text file inside the workspace.",
+                "parameters": {
+                    "type": "object",
+                    "properties": {
+                        "path": { "type": "string", "description": "Path relative to the workspace root." },
+                        "content": { "type": "string", "description": "The full UTF-8 content to write." }
+                    },
+                    "required": ["path", "content"]
+                }
+            }
+        },
+        {
+            "type": "function",
+            "function": {
+                "name": "search",
+                "description": "Recursively search files under a directory for lines matching a query.",
+                "parameters": {
+                    "type": "object",
+                    "properties": {
+                        "path": { "type": "string", "description": "Directory to search, relative to the workspace root." },
+                        "query": { "type": "string", "description": "Substring or regular expression to search for." },
+                        "regex": { "type": "boolean", "description": "Treat `query` as a regular expression instead of a plain substring." }
+                    },
+                    "required": ["path", "query", "regex"]
+                }
+            }
+        },
+        {
+            "type": "function",
+            "function": {
+                "name": "metadata",
+                "description": "Get size, modified time, and file-type metadata for a path.",
+                "parameters": {
+                    "type": "object",
+                    "properties": {
+                        "path": { "type": "string", "description": "Path relative to the workspace root." }
+                    },
+                    "required": ["path"]
+                }
+            }
+        },
+        {
+            "type": "function",
+            "function": {
+                "name": "remove",
+                "description": "Delete a file or directory inside the workspace.",
+                "parameters": {
+                    "type": "object",
+                    "properties": {
+                        "path": { "type": "string", "description": "Path relative to the workspace root." },
+                        "recursive": { "type": "boolean", "description": "Required to remove a non-empty directory." }
+                    },
+                    "required": ["path", "recursive"]
+                }
+            }
+        },
+        {
+            "type": "function",
+            "function": {
+                "name": "rename",
+                "description": "Rename or move a file or directory inside the workspace.",
+                "parameters": {
+                    "type": "object",
+                    "properties": {
+                        "src": { "type": "string", "description": "Source path relative to the workspace root." },
+                        "dst": { "type": "string", "description": "Destination path relative to the workspace root." }
+                    },
+                    "required": ["src", "dst"]
+                }
+            }
+        },
+        {
+            "type": "function",
+            "function": {
+                "name": "make_dir",
+                "description": "Create a directory (and any missing parents) inside the workspace.",
+                "parameters": {
+                    "type": "object",
+                    "properties": {
+                        "path": { "type": "string", "description": "Path relative to the workspace root." }
+                    },
+                    "required": ["path"]
+                }
+            }
+        },
+        {
+            "type": "function",
+            "function": {
+                "name": "cargo_check",
+                "description": "Run `cargo check` (or `cargo clippy`) and return structured compiler diagnostics.",
+                "parameters": {
+                    "type": "object",
+                    "properties": {
+                        "manifest_dir": { "type": "string", "description": "Directory containing Cargo.toml, relative to the workspace root." },
+                        "clippy": { "type": "boolean", "description": "Run `cargo clippy` instead of `cargo check`." }
+                    },
+                    "required": ["manifest_dir", "clippy"]
+                }
+            }
+        }
+    ])
 }
 
 /// Results returned back to the model after executing a tool.
@@ -43,11 +369,28 @@ enum ToolResult {
 struct Agent {
     client: Client,
     messages: Vec<ChatMessage>,
+    max_steps: usize,
+    config: Config,
+}
+
+/// Tools with no side effects can run concurrently; everything else must
+/// execute sequentially, in submission order, to avoid racing on a path.
+fn is_read_only(call: &ToolCall) -> bool {
+    matches!(
+        call,
+        ToolCall::ListDir { .. }
+            | ToolCall::ReadFile { .. }
+            | ToolCall::Search { .. }
+            | ToolCall::Metadata { .. }
+    )
 }
 
 impl Agent {
     /// Create a new agent with a system prompt that explains the tool protocol.
-    fn new(initial_user_task: &str) -> Self {
+    /// `initial_user_task` is optional: with none, the REPL prompts for the
+    /// first message instead of seeding one.
+    fn new(initial_user_task: Option<&str>, config: Config) -> Self {
+        let root = config.workspace_root.display();
         let system_prompt = format!(
             "\
 You are a Rust coding agent operating inside a local filesystem workspace.
@@ -55,7 +398,13 @@ You are a Rust coding agent operating inside a local filesystem workspace.
 Workspace root (you MUST NOT leave this directory): `{root}`.
 
 You cannot run shell commands or access the real OS directly.
-Instead, you use the following TOOLS by emitting **pure JSON** (no surrounding text):
+Your tools (list_dir, read_file, write_file, search, metadata, remove,
+rename, make_dir, cargo_check) are exposed natively via the `tools` field
+of the chat completions request, so you should invoke them using the
+model's normal tool-calling mechanism.
+
+If native tool calling isn't available, you may instead emit a **pure
+JSON** object (no surrounding text) of the same shape:
 
 1) List directory contents:
    {{\"tool\": \"list_dir\", \"path\": \"relative/path\"}}
@@ -66,20 +415,27 @@ Instead, you use the following TOOLS by emitting **pure JSON** (no surrounding t
 3) Write (create/overwrite) a file with UTF-8 content:
    {{\"tool\": \"write_file\", \"path\": \"relative/path\", \"content\": \"...\"}}
 
-Rules:
-- `path` is ALWAYS RELATIVE to the workspace root `{root}`.
-- NEVER include `..` in paths.
-- When you want to use a tool, respond with ONLY the JSON object, nothing else.
-- I (the system) will reply with a tool result in the form:
-  TOOL_RESULT: <json>
+4) Recursively search files for a query:
+   {{\"tool\": \"search\", \"path\": \"relative/path\", \"query\": \"...\", \"regex\": false}}
+
+5) Get size / modified time / file-type metadata:
+   {{\"tool\": \"metadata\", \"path\": \"relative/path\"}}
+
+6) Delete a file or directory:
+   {{\"tool\": \"remove\", \"path\": \"relative/path\", \"recursive\": false}}
 
-  where the JSON has the shape:
-    {{\"status\":\"ok\",\"result\":{{...}}}} or
-    {{\"status\":\"error\",\"message\":\"...\"}}
+7) Rename or move a file or directory:
+   {{\"tool\": \"rename\", \"src\": \"relative/path\", \"dst\": \"relative/path\"}}
 
-- After seeing a TOOL_RESULT, you may call another tool (again, with pure JSON),
-  or continue with normal reasoning and natural-language explanation.
+8) Create a directory (and any missing parents):
+   {{\"tool\": \"make_dir\", \"path\": \"relative/path\"}}
 
+9) Check that a crate compiles and read back the compiler diagnostics:
+   {{\"tool\": \"cargo_check\", \"manifest_dir\": \"relative/path\", \"clippy\": false}}
+
+Rules:
+- `path` is ALWAYS RELATIVE to the workspace root `{root}`.
+- NEVER include `..` in paths.
 - When you are FINISHED with the task, respond with a normal natural-language answer,
   describing what you did and showing the important code snippets.
 
@@ -88,68 +444,135 @@ Your main goal:
 - Create and update Rust source files as requested
 - Keep code idiomatic and compilable
 ",
-            root = WORKSPACE_ROOT
         );
 
         let mut messages = Vec::new();
-        messages.push(ChatMessage {
-            role: "system".into(),
-            content: system_prompt,
-        });
-
-        messages.push(ChatMessage {
-            role: "user".into(),
-            content: initial_user_task.to_string(),
-        });
+        messages.push(ChatMessage::system(system_prompt));
+        if let Some(task) = initial_user_task {
+            messages.push(ChatMessage::user(task));
+        }
 
         Agent {
             client: Client::new(),
             messages,
+            max_steps: DEFAULT_MAX_STEPS,
+            config,
+        }
+    }
+
+    /// Read the next line of input from stdin, handling the `:reset` /
+    /// `:history` / `:quit` meta-commands before anything reaches the model.
+    /// Returns `false` when the session should end.
+    fn prompt_for_input(&mut self) -> Result<bool> {
+        loop {
+            print!("\n> ");
+            std::io::stdout().flush().ok();
+
+            let mut line = String::new();
+            if std::io::stdin().read_line(&mut line)? == 0 {
+                // EOF, e.g. piped input ran out.
+                return Ok(false);
+            }
+            let line = line.trim();
+
+            match line {
+                ":quit" => return Ok(false),
+                ":reset" => {
+                    self.messages.truncate(1); // keep only the system prompt
+                    println!("[Agent] Conversation history reset.");
+                }
+                ":history" => {
+                    for message in &self.messages {
+                        println!("{}: {}", message.role, message.content.as_deref().unwrap_or(""));
+                    }
+                }
+                "" => {}
+                _ => {
+                    self.messages.push(ChatMessage::user(line));
+                    return Ok(true);
+                }
+            }
         }
     }
 
-    /// Main loop: calls the LLM, interprets tool calls, executes them, and feeds results back.
+    /// Main loop: calls the LLM, interprets tool calls, executes them, feeds
+    /// results back, and once the model gives a final answer, reads the next
+    /// turn from stdin so the session continues conversationally.
     async fn run(&mut self) -> Result<()> {
+        // `messages` holds only the system prompt when no initial task was given.
+        if self.messages.len() == 1 && !self.prompt_for_input()? {
+            return Ok(());
+        }
+
+        let mut steps = 0usize;
+
         loop {
+            steps += 1;
+            if steps > self.max_steps {
+                let message = format!(
+                    "Aborting turn: exceeded the maximum of {} agent steps without a final answer.",
+                    self.max_steps
+                );
+                println!("\n[Agent] {message}");
+                self.messages.push(ChatMessage::assistant(message));
+
+                if !self.prompt_for_input()? {
+                    break;
+                }
+                steps = 0; // a fresh REPL turn gets a fresh step budget
+                continue;
+            }
+
             let reply = self.call_llm().await?;
-            println!("\n[LLM raw reply]\n{reply}\n");
 
-            // NEW: try to extract JSON from possible ```json ... ``` markdown
-            let json_candidate = extract_json_from_markdown(&reply);
+            if let Some(tool_calls) = reply.tool_calls {
+                println!("\n[LLM tool_calls]\n{tool_calls:?}\n");
+
+                self.messages
+                    .push(ChatMessage::assistant_tool_calls(tool_calls.clone()));
+
+                let results = execute_tool_calls(&tool_calls, &self.config.workspace_root);
+                for (tc, result) in tool_calls.iter().zip(results) {
+                    let result_json = serde_json::to_string(&result)?;
+                    self.messages
+                        .push(ChatMessage::tool(tc.id.clone(), result_json));
+                }
+
+                // Loop again, giving the model the tool results.
+                continue;
+            }
+
+            // The streamed text has already been printed to stdout token by
+            // token inside `call_llm` as it arrived.
+            let content = reply.content.unwrap_or_default();
+
+            // Fallback for models that don't support native tool calling: sniff
+            // JSON out of the content, optionally fenced in a markdown code block.
+            let json_candidate = extract_json_from_markdown(&content);
 
-            // Try to parse the reply as a tool call (pure JSON).
             match serde_json::from_str::<ToolCall>(&json_candidate) {
                 Ok(tool_call) => {
-                    println!("[Agent] Detected tool call: {:?}", tool_call);
-                    // Record assistant's tool-call JSON as a message.
-                    self.messages.push(ChatMessage {
-                        role: "assistant".into(),
-                        content: format!("TOOL CALL"),
-                    });
-                    
-                    let result = execute_tool(tool_call);
-                    let result_json = serde_json::to_string(&result)?;
+                    println!("\n[Agent] Detected tool call (content fallback): {:?}", tool_call);
+                    self.messages.push(ChatMessage::assistant(content));
 
-                    // Record assistant's tool-call JSON as a message.
-                    self.messages.push(ChatMessage {
-                        role: "assistant".into(),
-                        content: reply,
-                    });
+                    let result = execute_tool(tool_call, &self.config.workspace_root);
+                    let result_json = serde_json::to_string(&result)?;
 
-                    // Provide tool result back as a new user message.
-                    self.messages.push(ChatMessage {
-                        role: "user".into(),
-                        content: format!("TOOL_RESULT: {result_json}"),
-                    });
-                    
+                    self.messages
+                        .push(ChatMessage::user(format!("TOOL_RESULT: {result_json}")));
 
                     // Loop again, giving the model the tool result.
                     continue;
                 }
                 Err(_) => {
-                    // Not valid ToolCall JSON â†’ treat as final natural-language answer and stop.
-                    println!("=== Final assistant answer ===\n{reply}");
-                    break;
+                    // Not valid ToolCall JSON â†’ treat as a final natural-language answer.
+                    println!("\n=== End of assistant turn ===");
+                    self.messages.push(ChatMessage::assistant(content));
+
+                    if !self.prompt_for_input()? {
+                        break;
+                    }
+                    steps = 0; // a fresh REPL turn gets a fresh step budget
                 }
             }
         }
@@ -157,13 +580,17 @@ Your main goal:
         Ok(())
     }
 
-    /// Call the local llama-server /v1/chat/completions endpoint.
-    async fn call_llm(&self) -> Result<String> {
-        let url = "http://127.0.0.1:8080/v1/chat/completions";
+    /// Call the local llama-server /v1/chat/completions endpoint over SSE,
+    /// printing content tokens as they arrive and assembling any tool calls
+    /// fragmented across deltas.
+    async fn call_llm(&self) -> Result<LlmReply> {
+        let url = format!("{}/v1/chat/completions", self.config.base_url);
         let body = json!({
-            "model": "qwen2.5-coder-7b", // must match --alias passed to llama-server
+            "model": self.config.model, // must match --alias passed to llama-server
             "messages": self.messages,
-            "stream": false
+            "tools": tool_schemas(),
+            "tool_choice": "auto",
+            "stream": true
         });
 
         let resp = self
@@ -183,34 +610,228 @@ Your main goal:
         }
 
         #[derive(Debug, Deserialize)]
-        struct Resp {
-            choices: Vec<Choice>,
+        struct StreamFrame {
+            choices: Vec<StreamChoice>,
         }
 
         #[derive(Debug, Deserialize)]
-        struct Choice {
-            message: LlmMessage,
+        struct StreamChoice {
+            #[serde(default)]
+            delta: Delta,
+        }
+
+        #[derive(Debug, Default, Deserialize)]
+        struct Delta {
+            #[serde(default)]
+            content: Option<String>,
+            #[serde(default)]
+            tool_calls: Option<Vec<DeltaToolCall>>,
         }
 
         #[derive(Debug, Deserialize)]
-        struct LlmMessage {
-            content: String,
+        struct DeltaToolCall {
+            index: u64,
+            #[serde(default)]
+            id: Option<String>,
+            #[serde(default)]
+            function: Option<DeltaFunction>,
+        }
+
+        #[derive(Debug, Default, Deserialize)]
+        struct DeltaFunction {
+            #[serde(default)]
+            name: Option<String>,
+            #[serde(default)]
+            arguments: Option<String>,
+        }
+
+        /// Accumulates the fragments of a single `tool_calls[index]` entry
+        /// across however many SSE frames it is split over.
+        struct ToolCallAccum {
+            id: Option<String>,
+            name: String,
+            arguments: String,
+        }
+
+        let mut content = String::new();
+        let mut tool_calls: BTreeMap<u64, ToolCallAccum> = BTreeMap::new();
+        let mut buffer = String::new();
+        // Bytes carried over from the previous chunk that didn't form a
+        // complete UTF-8 sequence yet; a multi-byte character can straddle
+        // a `bytes_stream()` chunk boundary at any point.
+        let mut pending_bytes: Vec<u8> = Vec::new();
+        let mut stream = resp.bytes_stream();
+
+        while let Some(chunk) = stream.next().await {
+            pending_bytes.extend_from_slice(&chunk?);
+            decode_utf8_prefix(&mut pending_bytes, &mut buffer);
+
+            while let Some(pos) = buffer.find('\n') {
+                let line = buffer[..pos].trim().to_string();
+                buffer.drain(..=pos);
+
+                let Some(data) = line.strip_prefix("data: ") else {
+                    continue;
+                };
+                if data == "[DONE]" {
+                    continue;
+                }
+
+                let Ok(frame) = serde_json::from_str::<StreamFrame>(data) else {
+                    continue;
+                };
+                let Some(choice) = frame.choices.into_iter().next() else {
+                    continue;
+                };
+
+                if let Some(text) = choice.delta.content {
+                    print!("{text}");
+                    std::io::stdout().flush().ok();
+                    content.push_str(&text);
+                }
+
+                for delta in choice.delta.tool_calls.into_iter().flatten() {
+                    let accum = tool_calls.entry(delta.index).or_insert(ToolCallAccum {
+                        id: None,
+                        name: String::new(),
+                        arguments: String::new(),
+                    });
+                    if let Some(id) = delta.id {
+                        accum.id = Some(id);
+                    }
+                    if let Some(function) = delta.function {
+                        if let Some(name) = function.name {
+                            accum.name.push_str(&name);
+                        }
+                        if let Some(arguments) = function.arguments {
+                            accum.arguments.push_str(&arguments);
+                        }
+                    }
+                }
+            }
         }
 
-        let parsed: Resp = resp.json().await?;
-        let content = parsed
-            .choices
-            .get(0)
-            .map(|c| c.message.content.clone())
-            .unwrap_or_default();
+        let tool_calls: Vec<ToolCallResponse> = tool_calls
+            .into_iter()
+            .enumerate()
+            .map(|(i, (_, accum))| ToolCallResponse {
+                id: accum.id.unwrap_or_else(|| format!("call_{i}")),
+                kind: "function".into(),
+                function: FunctionCall {
+                    name: accum.name,
+                    arguments: accum.arguments,
+                },
+            })
+            .collect();
+
+        Ok(LlmReply {
+            content: if content.is_empty() { None } else { Some(content) },
+            tool_calls: if tool_calls.is_empty() {
+                None
+            } else {
+                Some(tool_calls)
+            },
+        })
+    }
+}
+
+/// The content and/or native tool calls returned by a single [`Agent::call_llm`] turn.
+struct LlmReply {
+    content: Option<String>,
+    tool_calls: Option<Vec<ToolCallResponse>>,
+}
 
-        Ok(content)
+/// Move as much of `pending` as forms complete UTF-8 text onto the end of
+/// `out`, leaving behind only the trailing bytes of a multi-byte character
+/// that a `bytes_stream()` chunk boundary may have split mid-sequence.
+fn decode_utf8_prefix(pending: &mut Vec<u8>, out: &mut String) {
+    match std::str::from_utf8(pending) {
+        Ok(text) => {
+            out.push_str(text);
+            pending.clear();
+        }
+        Err(e) => {
+            let valid_up_to = e.valid_up_to();
+            out.push_str(std::str::from_utf8(&pending[..valid_up_to]).unwrap());
+            pending.drain(..valid_up_to);
+        }
     }
 }
 
-/// Ensure `rel` is a safe relative path (no `..`).
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_utf8_prefix_handles_char_split_across_chunks() {
+        let full = "café 😀".as_bytes().to_vec();
+        let mut out = String::new();
+        let mut pending = Vec::new();
+
+        for byte in full {
+            pending.push(byte);
+            decode_utf8_prefix(&mut pending, &mut out);
+        }
+
+        assert_eq!(out, "café 😀");
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn resolve_workspace_path_rejects_symlink_escaping_workspace() {
+        let tmp = std::env::temp_dir().join(format!(
+            "illama-test-{}-{}",
+            std::process::id(),
+            std::time::SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+        ));
+        let workspace = tmp.join("workspace");
+        let outside = tmp.join("outside");
+        fs::create_dir_all(&workspace).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        fs::write(outside.join("secret.txt"), "TOP_SECRET_DATA_OUTSIDE").unwrap();
+
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(outside.join("secret.txt"), workspace.join("link.txt")).unwrap();
+
+        let result = resolve_workspace_path(&workspace, "link.txt");
+
+        #[cfg(unix)]
+        assert!(result.is_err(), "symlink escaping the workspace must be rejected");
+
+        fs::remove_dir_all(&tmp).ok();
+    }
+
+    #[test]
+    fn resolve_workspace_path_accepts_path_inside_workspace() {
+        let tmp = std::env::temp_dir().join(format!(
+            "illama-test-ok-{}-{}",
+            std::process::id(),
+            std::time::SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+        ));
+        fs::create_dir_all(&tmp).unwrap();
+        fs::write(tmp.join("inside.txt"), "fine").unwrap();
+
+        let result = resolve_workspace_path(&tmp, "inside.txt");
+        assert!(result.is_ok());
+
+        fs::remove_dir_all(&tmp).ok();
+    }
+}
+
+/// Ensure `rel` is a safe relative path: no `..` components, and not already
+/// absolute (an absolute path would make `Path::join` discard the workspace
+/// root entirely).
 fn ensure_safe_relative(rel: &str) -> Result<(), String> {
     let p = Path::new(rel);
+    if p.is_absolute() {
+        return Err(format!("Path must be relative, not absolute: {rel}"));
+    }
     for comp in p.components() {
         if let Component::ParentDir = comp {
             return Err(format!(
@@ -221,20 +842,125 @@ fn ensure_safe_relative(rel: &str) -> Result<(), String> {
     Ok(())
 }
 
-/// Convert a relative path into an absolute path under WORKSPACE_ROOT.
-fn resolve_workspace_path(rel: &str) -> Result<PathBuf, String> {
+/// Canonicalize `path`, walking up to the nearest existing ancestor if the
+/// full path doesn't exist yet (e.g. a file about to be created with
+/// `write_file`), then re-appending the non-existent tail. This still
+/// resolves any symlink among the existing ancestors; a dangling symlink
+/// counts as existing, so canonicalizing it fails instead of letting it through.
+fn canonicalize_as_far_as_possible(path: &Path) -> std::io::Result<PathBuf> {
+    let mut existing = path;
+    let mut tail = Vec::new();
+
+    while existing.symlink_metadata().is_err() {
+        match existing.parent() {
+            Some(parent) => {
+                tail.push(existing.file_name().unwrap_or_default().to_os_string());
+                existing = parent;
+            }
+            None => break,
+        }
+    }
+
+    let mut canonical = existing.canonicalize()?;
+    for component in tail.into_iter().rev() {
+        canonical.push(component);
+    }
+    Ok(canonical)
+}
+
+/// Convert a relative path into an absolute path under `workspace_root`,
+/// canonicalizing the result and verifying it still starts with the
+/// canonicalized root so neither a symlink nor an absolute-path injection
+/// can escape the sandbox.
+///
+/// This only guards the single path it's given: a tool that recurses into a
+/// directory on its own (e.g. `search`'s walk) must reject symlinks at every
+/// entry it visits, not just validate the starting path through here.
+fn resolve_workspace_path(workspace_root: &Path, rel: &str) -> Result<PathBuf, String> {
     ensure_safe_relative(rel)?;
-    let root = Path::new(WORKSPACE_ROOT);
-    Ok(root.join(rel))
+
+    let candidate = workspace_root.join(rel);
+
+    let canonical_root = workspace_root
+        .canonicalize()
+        .map_err(|e| format!("Failed to canonicalize workspace root {}: {e}", workspace_root.display()))?;
+    let canonical_candidate = canonicalize_as_far_as_possible(&candidate)
+        .map_err(|e| format!("Failed to resolve {}: {e}", candidate.display()))?;
+
+    if !canonical_candidate.starts_with(&canonical_root) {
+        return Err(format!(
+            "Path escapes the workspace root via a symlink or absolute injection: {rel}"
+        ));
+    }
+
+    Ok(candidate)
 }
 
+/// Execute a batch of tool calls from a single assistant turn. Read-only
+/// calls (`list_dir`, `read_file`, `search`, `metadata`) run concurrently on a
+/// `num_cpus`-sized thread pool; everything else runs sequentially afterward,
+/// in submission order, so writes can't race on the same path. Results are
+/// returned in the same order as `tool_calls`.
+fn execute_tool_calls(tool_calls: &[ToolCallResponse], workspace_root: &Path) -> Vec<ToolResult> {
+    let mut parsed: Vec<Option<ToolCall>> = Vec::with_capacity(tool_calls.len());
+    let mut results: Vec<Option<ToolResult>> = Vec::with_capacity(tool_calls.len());
+
+    for tc in tool_calls {
+        match tool_call_from_function(&tc.function) {
+            Ok(call) => {
+                parsed.push(Some(call));
+                results.push(None);
+            }
+            Err(message) => {
+                parsed.push(None);
+                results.push(Some(ToolResult::Error { message }));
+            }
+        }
+    }
+
+    let pool = ThreadPool::new(num_cpus::get().max(1));
+    let (tx, rx) = mpsc::channel();
+    let mut dispatched = 0;
+
+    for (i, call) in parsed.iter_mut().enumerate() {
+        let is_read_only_call = call.as_ref().is_some_and(is_read_only);
+        if !is_read_only_call {
+            continue;
+        }
+        let call = call.take().expect("checked Some above");
+        let tx = tx.clone();
+        let workspace_root = workspace_root.to_path_buf();
+        dispatched += 1;
+        pool.execute(move || {
+            println!("[Agent] Detected tool call: {:?}", call);
+            let result = execute_tool(call, &workspace_root);
+            let _ = tx.send((i, result));
+        });
+    }
+    drop(tx);
+
+    for (i, result) in rx.iter().take(dispatched) {
+        results[i] = Some(result);
+    }
+
+    for (i, call) in parsed.into_iter().enumerate() {
+        if let Some(call) = call {
+            println!("[Agent] Detected tool call: {:?}", call);
+            results[i] = Some(execute_tool(call, workspace_root));
+        }
+    }
 
+    results
+        .into_iter()
+        .map(|r| r.expect("every tool call produces exactly one result"))
+        .collect()
+}
 
-/// Execute a tool call against the local filesystem, sandboxed to WORKSPACE_ROOT.
-fn execute_tool(call: ToolCall) -> ToolResult {
+/// Execute a tool call against the local filesystem, sandboxed to `workspace_root`.
+fn execute_tool(call: ToolCall, workspace_root: &Path) -> ToolResult {
     match call {
         ToolCall::ListDir { path } => {
-            match resolve_workspace_path(&path).and_then(|p| {
+            match resolve_workspace_path(workspace_root, &path).and_then(|p| {
                 let mut entries = Vec::new();
                 let read_dir = fs::read_dir(&p)
                     .map_err(|e| format!("read_dir failed on {}: {e}", p.display()))?;
@@ -256,7 +982,7 @@ fn execute_tool(call: ToolCall) -> ToolResult {
             }
         }
         ToolCall::ReadFile { path } => {
-            match resolve_workspace_path(&path).and_then(|p| {
+            match resolve_workspace_path(workspace_root, &path).and_then(|p| {
                 fs::read_to_string(&p)
                     .map_err(|e| format!("Failed to read {}: {e}", p.display()))
             }) {
@@ -268,7 +994,7 @@ fn execute_tool(call: ToolCall) -> ToolResult {
         }
         ToolCall::WriteFile { path, content } => {
             println!("[DEBUG] path = {}, content = {}", path, content);
-            match resolve_workspace_path(&path).and_then(|p| {
+            match resolve_workspace_path(workspace_root, &path).and_then(|p| {
                 if let Some(parent) = p.parent() {
                     fs::create_dir_all(parent)
                         .map_err(|e| format!("Failed to create dirs {}: {e}", parent.display()))?;
@@ -282,21 +1008,275 @@ fn execute_tool(call: ToolCall) -> ToolResult {
                 Err(msg) => ToolResult::Error { message: msg },
             }
         }
+        ToolCall::Search { path, query, regex } => {
+            match resolve_workspace_path(workspace_root, &path).and_then(|p| search_path(&p, &query, regex)) {
+                Ok(matches) => ToolResult::Ok {
+                    result: json!(matches),
+                },
+                Err(msg) => ToolResult::Error { message: msg },
+            }
+        }
+        ToolCall::Metadata { path } => {
+            match resolve_workspace_path(workspace_root, &path).and_then(|p| {
+                let meta = fs::metadata(&p).map_err(|e| format!("Failed to stat {}: {e}", p.display()))?;
+                let symlink_meta = fs::symlink_metadata(&p)
+                    .map_err(|e| format!("Failed to lstat {}: {e}", p.display()))?;
+                let modified_unix = meta
+                    .modified()
+                    .ok()
+                    .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                    .map(|d| d.as_secs());
+                Ok(json!({
+                    "size": meta.len(),
+                    "modified_unix": modified_unix,
+                    "is_dir": meta.is_dir(),
+                    "is_file": meta.is_file(),
+                    "is_symlink": symlink_meta.file_type().is_symlink(),
+                }))
+            }) {
+                Ok(result) => ToolResult::Ok { result },
+                Err(msg) => ToolResult::Error { message: msg },
+            }
+        }
+        ToolCall::Remove { path, recursive } => {
+            match resolve_workspace_path(workspace_root, &path).and_then(|p| {
+                let meta = fs::symlink_metadata(&p)
+                    .map_err(|e| format!("Failed to stat {}: {e}", p.display()))?;
+                if meta.is_dir() {
+                    if recursive {
+                        fs::remove_dir_all(&p)
+                    } else {
+                        fs::remove_dir(&p)
+                    }
+                } else {
+                    fs::remove_file(&p)
+                }
+                .map_err(|e| format!("Failed to remove {}: {e}", p.display()))
+            }) {
+                Ok(()) => ToolResult::Ok {
+                    result: json!({ "removed": true }),
+                },
+                Err(msg) => ToolResult::Error { message: msg },
+            }
+        }
+        ToolCall::Rename { src, dst } => {
+            match resolve_workspace_path(workspace_root, &src).and_then(|src_p| {
+                resolve_workspace_path(workspace_root, &dst).and_then(|dst_p| {
+                    if let Some(parent) = dst_p.parent() {
+                        fs::create_dir_all(parent)
+                            .map_err(|e| format!("Failed to create dirs {}: {e}", parent.display()))?;
+                    }
+                    fs::rename(&src_p, &dst_p).map_err(|e| {
+                        format!("Failed to rename {} to {}: {e}", src_p.display(), dst_p.display())
+                    })
+                })
+            }) {
+                Ok(()) => ToolResult::Ok {
+                    result: json!({ "renamed": true }),
+                },
+                Err(msg) => ToolResult::Error { message: msg },
+            }
+        }
+        ToolCall::MakeDir { path } => {
+            match resolve_workspace_path(workspace_root, &path)
+                .and_then(|p| fs::create_dir_all(&p).map_err(|e| format!("Failed to create dir {}: {e}", p.display())))
+            {
+                Ok(()) => ToolResult::Ok {
+                    result: json!({ "created": true }),
+                },
+                Err(msg) => ToolResult::Error { message: msg },
+            }
+        }
+        ToolCall::CargoCheck { manifest_dir, clippy } => {
+            match resolve_workspace_path(workspace_root, &manifest_dir).and_then(|p| run_cargo_check(&p, clippy)) {
+                Ok((diagnostics, success)) => ToolResult::Ok {
+                    result: json!({ "success": success, "diagnostics": diagnostics }),
+                },
+                Err(msg) => ToolResult::Error { message: msg },
+            }
+        }
     }
 }
 
+/// Run `cargo check` (or `cargo clippy`) inside `manifest_dir` and collapse the
+/// newline-delimited `compiler-message` objects into a compact diagnostic list.
+/// Only `cargo` is ever spawned, and only with a `current_dir` already validated
+/// by `resolve_workspace_path`, so this cannot escape the sandbox into an
+/// arbitrary shell.
+fn run_cargo_check(manifest_dir: &Path, clippy: bool) -> Result<(Vec<serde_json::Value>, bool), String> {
+    let subcommand = if clippy { "clippy" } else { "check" };
+
+    let output = Command::new("cargo")
+        .arg(subcommand)
+        .arg("--message-format=json")
+        .current_dir(manifest_dir)
+        .output()
+        .map_err(|e| format!("Failed to run cargo {subcommand} in {}: {e}", manifest_dir.display()))?;
+
+    let mut diagnostics = Vec::new();
+    let mut has_error = false;
+
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        let Ok(value) = serde_json::from_str::<serde_json::Value>(line) else {
+            continue;
+        };
+        if value.get("reason").and_then(|r| r.as_str()) != Some("compiler-message") {
+            continue;
+        }
+        let Some(message) = value.get("message") else {
+            continue;
+        };
+
+        let level = message
+            .get("level")
+            .and_then(|v| v.as_str())
+            .unwrap_or("")
+            .to_string();
+        let text = message
+            .get("message")
+            .and_then(|v| v.as_str())
+            .unwrap_or("")
+            .to_string();
+        let rendered = message
+            .get("rendered")
+            .and_then(|v| v.as_str())
+            .unwrap_or("")
+            .to_string();
+
+        let primary_span = message.get("spans").and_then(|s| s.as_array()).and_then(|spans| {
+            spans
+                .iter()
+                .find(|s| s.get("is_primary").and_then(|p| p.as_bool()) == Some(true))
+        });
+        let file_name = primary_span
+            .and_then(|s| s.get("file_name"))
+            .and_then(|v| v.as_str())
+            .unwrap_or("")
+            .to_string();
+        let line_start = primary_span
+            .and_then(|s| s.get("line_start"))
+            .and_then(|v| v.as_u64())
+            .unwrap_or(0);
+        let column_start = primary_span
+            .and_then(|s| s.get("column_start"))
+            .and_then(|v| v.as_u64())
+            .unwrap_or(0);
+
+        if level == "error" {
+            has_error = true;
+        }
+
+        diagnostics.push(json!({
+            "level": level,
+            "message": text,
+            "file_name": file_name,
+            "line_start": line_start,
+            "column_start": column_start,
+            "rendered": rendered,
+        }));
+    }
+
+    // `cargo` can fail before emitting a single `compiler-message` (no
+    // `Cargo.toml` in `manifest_dir`, a malformed manifest, a missing
+    // toolchain, ...) — in that case the error only shows up on stderr and
+    // the exit status, so fall back to that rather than reporting success.
+    if diagnostics.is_empty() && !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+        diagnostics.push(json!({
+            "level": "error",
+            "message": stderr,
+            "file_name": "",
+            "line_start": 0,
+            "column_start": 0,
+            "rendered": stderr,
+        }));
+        has_error = true;
+    }
+
+    Ok((diagnostics, !has_error))
+}
+
+/// Recursively search `root` for lines matching `query`, treating it as a
+/// regular expression when `use_regex` is set and as a plain substring otherwise.
+fn search_path(root: &Path, query: &str, use_regex: bool) -> Result<Vec<serde_json::Value>, String> {
+    let pattern = if use_regex {
+        Some(Regex::new(query).map_err(|e| format!("invalid regex '{query}': {e}"))?)
+    } else {
+        None
+    };
+
+    let mut matches = Vec::new();
+    let mut stack = vec![root.to_path_buf()];
+
+    while let Some(dir) = stack.pop() {
+        let entries =
+            fs::read_dir(&dir).map_err(|e| format!("read_dir failed on {}: {e}", dir.display()))?;
+        for entry in entries {
+            let entry = entry.map_err(|e| e.to_string())?;
+            let file_type = entry.file_type().map_err(|e| e.to_string())?;
+            let path = entry.path();
+
+            // `file_type()` does not follow symlinks, so a symlinked dir is
+            // neither `is_dir()` nor `is_file()` here; skip it outright
+            // rather than falling through to `read_to_string`, which would
+            // follow it straight out of the workspace sandbox.
+            if file_type.is_symlink() {
+                continue;
+            }
+
+            if file_type.is_dir() {
+                stack.push(path);
+                continue;
+            }
+
+            // Skip binary/non-UTF-8 files rather than failing the whole search.
+            let Ok(content) = fs::read_to_string(&path) else {
+                continue;
+            };
+
+            for (i, line) in content.lines().enumerate() {
+                let is_match = match &pattern {
+                    Some(re) => re.is_match(line),
+                    None => line.contains(query),
+                };
+                if is_match {
+                    matches.push(json!({
+                        "path": path.display().to_string(),
+                        "line": i + 1,
+                        "text": line,
+                    }));
+                }
+            }
+        }
+    }
+
+    Ok(matches)
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    // You can change this to whatever task you want the agent to do.
-    let initial_task = "\
-create a text file in the workspace with the contents 'hello world'";
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (config, rest) = Config::from_args(&args);
+
+    // Any remaining, non-flag arguments become the initial task; with none,
+    // the REPL prompts for one instead.
+    let initial_task = if rest.is_empty() {
+        None
+    } else {
+        Some(rest.join(" "))
+    };
 
-    println!("[Agent] Workspace root: {WORKSPACE_ROOT}");
-    println!("[Agent] Initial task: {initial_task}");
+    println!("[Agent] Workspace root: {}", config.workspace_root.display());
+    println!("[Agent] Model: {}", config.model);
+    println!("[Agent] Base URL: {}", config.base_url);
+    match &initial_task {
+        Some(task) => println!("[Agent] Initial task: {task}"),
+        None => println!("[Agent] No initial task given; type one at the `>` prompt."),
+    }
 
-    let mut agent = Agent::new(initial_task);
+    let mut agent = Agent::new(initial_task.as_deref(), config);
     agent.run().await?;
-    
+
     Ok(())
 }
 